@@ -1,23 +1,193 @@
 use anyhow::{Context, Result};
 use colored::{control, Colorize};
-use itertools::Itertools;
 use ptree::{
     print_tree_with,
     style::{Color, Style},
     PrintConfig, TreeBuilder,
 };
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashSet},
     io,
     path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
 };
 use structopt::StructOpt;
 use walkdir::WalkDir;
 
 // Map with the directory as key, and as value
-// (Vec<children_directories>, inode_count, updated)
-type NodeMap = BTreeMap<PathBuf, (Vec<PathBuf>, usize, bool)>;
+// (Vec<children_directories>, inode_count, size_in_bytes, updated, Vec<plain_file_children>)
+// `plain_file_children` is only populated for directories visited by the
+// shallow walk in `process_root` (i.e. down to `--depth`); it's for display
+// only and plays no part in the count/size folded by `update_node`.
+type NodeMap = BTreeMap<PathBuf, (Vec<PathBuf>, usize, u64, bool, Vec<PathBuf>)>;
+
+// Bumped whenever the on-disk cache format changes, so a cache written by an
+// older/newer binary is rejected instead of misread.
+const CACHE_VERSION: u32 = 2;
+
+// The subset of `Opt` that affects counting. A cache built under a different
+// flag set is meaningless (e.g. a dedup'd count cached under `--size` off),
+// so it's stored alongside the counts and checked on load.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CacheFlags {
+    show_hidden: bool,
+    dedup_inodes: bool,
+    size: bool,
+    apparent_size: bool,
+    one_file_system: bool,
+    follow_symlinks: bool,
+}
+
+impl CacheFlags {
+    fn from_opt(opt: &Opt) -> Self {
+        CacheFlags {
+            show_hidden: opt.show_hidden,
+            dedup_inodes: opt.dedup_inodes,
+            size: opt.size,
+            apparent_size: opt.apparent_size,
+            one_file_system: opt.one_file_system,
+            follow_symlinks: opt.follow_symlinks,
+        }
+    }
+}
+
+// The cached result of one `count_dir_inodes` call: the directory's mtime at
+// the time it was counted (seconds since the epoch), and the resulting
+// count/size. If the directory's mtime hasn't changed, these are reused
+// instead of re-walking the whole subtree.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedSubtree {
+    mtime: u64,
+    count: usize,
+    size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Cache {
+    version: u32,
+    flags: CacheFlags,
+    subtrees: BTreeMap<PathBuf, CachedSubtree>,
+}
+
+fn mtime_secs<P: AsRef<Path>>(path: P) -> Option<u64> {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs())
+}
+
+fn load_cache(path: &Path, flags: &CacheFlags) -> Option<Cache> {
+    let bytes = std::fs::read(path).ok()?;
+    let cache: Cache = bincode::deserialize(&bytes).ok()?;
+    if cache.version != CACHE_VERSION || &cache.flags != flags {
+        return None;
+    }
+    Some(cache)
+}
+
+fn save_cache(path: &Path, cache: &Cache) -> Result<()> {
+    let bytes = bincode::serialize(cache).context("Could not serialize cache")?;
+    std::fs::write(path, bytes).context(format!("Could not write cache to {path:?}"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortOrder {
+    Count,
+    Name,
+    None,
+}
+
+impl std::str::FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "count" => Ok(SortOrder::Count),
+            "name" => Ok(SortOrder::Name),
+            "none" => Ok(SortOrder::None),
+            other => Err(format!(
+                "Unknown sort order {other:?}, expected one of: count, name, none"
+            )),
+        }
+    }
+}
+
+// A tree entry: either a directory (with its own folded count, shown with
+// its own subtree) or a plain file (a leaf, with no meaningful count of its
+// own to sort or display).
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Child {
+    Dir(PathBuf),
+    File(PathBuf),
+}
+
+impl Child {
+    fn path(&self) -> &Path {
+        match self {
+            Child::Dir(path) | Child::File(path) => path,
+        }
+    }
+}
+
+// Orders `dirs` and `files` per `sort`/`reverse`, then drops any directory
+// whose (already folded) count is below `threshold` so it's collapsed into
+// its parent's total instead of getting its own line in the tree. Files
+// have no meaningful count to threshold against, so they're never dropped.
+fn ordered_children(
+    dirs: &[PathBuf],
+    files: &[PathBuf],
+    map: &NodeMap,
+    sort: SortOrder,
+    reverse: bool,
+    threshold: usize,
+) -> Vec<Child> {
+    let mut dirs: Vec<_> = dirs
+        .iter()
+        .filter(|dir| map.get(*dir).map(|node| node.1).unwrap_or(0) >= threshold)
+        .cloned()
+        .collect();
+    let mut files = files.to_vec();
+
+    let mut children = match sort {
+        SortOrder::None => {
+            let mut children: Vec<_> = dirs.into_iter().map(Child::Dir).collect();
+            children.extend(files.into_iter().map(Child::File));
+            children
+        }
+        SortOrder::Name => {
+            dirs.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+            files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+            let mut children: Vec<_> = dirs.into_iter().map(Child::Dir).collect();
+            children.extend(files.into_iter().map(Child::File));
+            children.sort_by(|a, b| a.path().file_name().cmp(&b.path().file_name()));
+            children
+        }
+        SortOrder::Count => {
+            dirs.sort_by(|a, b| {
+                let count_a = map.get(a).unwrap().1;
+                let count_b = map.get(b).unwrap().1;
+                Ord::cmp(&count_b, &count_a)
+            });
+            files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+            // Files carry no meaningful count, so they're listed after all
+            // directories rather than interleaved by count.
+            let mut children: Vec<_> = dirs.into_iter().map(Child::Dir).collect();
+            children.extend(files.into_iter().map(Child::File));
+            children
+        }
+    };
+
+    if reverse {
+        children.reverse();
+    }
+
+    children
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "icounter", about = "Count inodes in a directory structure.")]
@@ -38,8 +208,62 @@ struct Opt {
     #[structopt(short, long, default_value = "0")]
     depth: usize,
 
-    /// Root to count inodes from
-    root: PathBuf,
+    /// Count each distinct inode once, instead of once per hard-linked entry
+    #[structopt(short = "u", long)]
+    dedup_inodes: bool,
+
+    /// Show aggregated disk usage for each directory alongside the inode count
+    #[structopt(short = "S", long)]
+    size: bool,
+
+    /// When showing size, use apparent (logical) file length instead of allocated on-disk blocks
+    #[structopt(short = "A", long)]
+    apparent_size: bool,
+
+    /// Don't descend into mount points; stay on the root's filesystem
+    #[structopt(short = "x", long)]
+    one_file_system: bool,
+
+    /// Persist counted subtrees to this file and reuse them on the next run,
+    /// only rescanning subtrees whose mtime has changed. Not yet supported
+    /// together with --size: a directory's mtime doesn't change when a file
+    /// already inside it is overwritten, so a cached size would go stale.
+    #[structopt(long)]
+    cache: Option<PathBuf>,
+
+    /// How to order sibling directories
+    #[structopt(long, default_value = "count")]
+    sort: SortOrder,
+
+    /// Reverse the chosen sort order
+    #[structopt(short, long)]
+    reverse: bool,
+
+    /// Collapse directories whose inode count is below this threshold into their parent
+    #[structopt(long, default_value = "0")]
+    threshold: usize,
+
+    /// Follow symlinked directories instead of counting the link itself as one inode
+    #[structopt(short = "L", long)]
+    follow_symlinks: bool,
+
+    /// Root(s) to count inodes from. Multiple roots are counted independently
+    /// and merged into a single comparison tree.
+    #[structopt(required = true)]
+    roots: Vec<PathBuf>,
+}
+
+// Rejects flag combinations that parse fine individually but aren't
+// meaningful together.
+fn validate_opt(opt: &Opt) -> Result<()> {
+    if opt.cache.is_some() && opt.size {
+        anyhow::bail!(
+            "--cache does not yet track file-level mtimes, so it can't safely be combined \
+             with --size: overwriting a file's contents changes that file's mtime but not \
+             its parent directory's, and the cached size would never be invalidated"
+        );
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -49,25 +273,191 @@ fn main() -> Result<()> {
         control::set_override(false);
     }
 
+    validate_opt(&opt)?;
+
     // Enable parallelism on children regardless of chosen display depth
     let max_depth = opt.depth.max(1);
 
+    let cache_flags = CacheFlags::from_opt(&opt);
+    let loaded_cache = opt
+        .cache
+        .as_ref()
+        .and_then(|path| load_cache(path, &cache_flags));
+
     let mut map: NodeMap = BTreeMap::new();
-    map.insert(opt.root.clone(), (vec![], 1, false));
+    let mut new_subtrees = BTreeMap::new();
+    for root in &opt.roots {
+        process_root(
+            &opt,
+            root,
+            max_depth,
+            &mut map,
+            loaded_cache.as_ref(),
+            &mut new_subtrees,
+        )?;
+    }
+
+    if let Some(cache_path) = &opt.cache {
+        let cache = Cache {
+            version: CACHE_VERSION,
+            flags: cache_flags,
+            subtrees: new_subtrees,
+        };
+        save_cache(cache_path, &cache)?;
+    }
+
+    // A single root is shown under its own name, with its directories and
+    // plain files as children. Multiple roots are merged under a synthetic
+    // top-level label, each root becoming one of its children, so they can
+    // be compared in one tree ordered per `--sort`/`--reverse`/`--threshold`.
+    let (root_name, total_count, total_size, dirs, files): (
+        String,
+        usize,
+        u64,
+        Vec<PathBuf>,
+        Vec<PathBuf>,
+    ) = if let [root] = opt.roots.as_slice() {
+        let name = match root.file_name() {
+            Some(p) => p.to_str(),
+            None => root.to_str(),
+        }
+        .context(format!("Could not convert {root:?} to string"))?
+        .to_owned();
+        let node = map
+            .get(root)
+            .context(format!("Root node {root:?} not found"))?;
+        (name, node.1, node.2, node.0.clone(), node.4.clone())
+    } else {
+        let mut total_count = 0;
+        let mut total_size = 0;
+        for root in &opt.roots {
+            let node = map
+                .get(root)
+                .context(format!("Root node {root:?} not found"))?;
+            total_count += node.1;
+            total_size += node.2;
+        }
+        (
+            format!("{} roots", opt.roots.len()),
+            total_count,
+            total_size,
+            opt.roots.clone(),
+            vec![],
+        )
+    };
+
+    let root_string = format_node(
+        &root_name,
+        total_count,
+        total_size,
+        100.,
+        opt.show_percent,
+        opt.size,
+    );
+
+    let config = if opt.ignore_colors {
+        PrintConfig::default()
+    } else {
+        let mut config = PrintConfig::from_env();
+        config.branch = Style {
+            foreground: Some(Color::Blue),
+            ..Style::default()
+        };
+        config
+    };
+
+    if opt.depth == 0 {
+        println!("{root_string}");
+    } else {
+        let mut tree = TreeBuilder::new(root_string);
+        for child in ordered_children(&dirs, &files, &map, opt.sort, opt.reverse, opt.threshold) {
+            print_node(
+                &mut tree,
+                &child,
+                &mut map,
+                total_count,
+                opt.show_percent,
+                opt.size,
+                opt.sort,
+                opt.reverse,
+                opt.threshold,
+            )?;
+        }
+        print_tree_with(&tree.build(), &config)?;
+        println!();
+    }
+
+    Ok(())
+}
+
+// Walks and counts a single root, inserting its subtree into `map`. Each root
+// is counted fully independently (its own hard-link dedup set included), so
+// that multiple roots passed on the command line don't influence each other.
+fn process_root(
+    opt: &Opt,
+    root: &Path,
+    max_depth: usize,
+    map: &mut NodeMap,
+    cache: Option<&Cache>,
+    new_subtrees: &mut BTreeMap<PathBuf, CachedSubtree>,
+) -> Result<()> {
+    let root_size = if opt.size {
+        std::fs::metadata(root)
+            .map(|metadata| size_from_metadata(&metadata, opt.apparent_size))
+            .unwrap_or(0)
+    } else {
+        0
+    };
+    map.insert(root.to_owned(), (vec![], 1, root_size, false, vec![]));
+
+    // Recorded once so every entry in this root's walk, including those
+    // handed off to `count_dir_inodes`, is compared against the root's own
+    // filesystem rather than whatever subtree they happen to be in.
+    let root_dev = if opt.one_file_system {
+        std::fs::metadata(root).ok().map(|m| device_id(&m))
+    } else {
+        None
+    };
 
     let mut to_count = vec![];
 
-    for entry in WalkDir::new(opt.root.clone())
+    // Shared across the sequential walk below and the parallel subtree
+    // counting further down, so a hard link is only counted once no matter
+    // which side of that split it falls on.
+    let seen_inodes: Mutex<HashSet<(u64, u64)>> = Mutex::new(HashSet::new());
+
+    // Nothing is open above `root` from this root's own point of view, so
+    // its cycle guard starts with an empty ancestor set.
+    let no_ancestors: HashSet<(u64, u64)> = HashSet::new();
+    let mut open_dirs: Vec<(u64, u64, usize)> = vec![];
+
+    for entry in WalkDir::new(root)
         .max_depth(max_depth)
+        .follow_links(opt.follow_symlinks)
         .into_iter()
-        .filter_entry(|e| opt.show_hidden || !is_hidden(e))
+        .filter_entry(|e| {
+            (opt.show_hidden || !is_hidden(e))
+                && on_device(e, opt.one_file_system, root_dev)
+                && allow_descent(e, opt.follow_symlinks, &no_ancestors, &mut open_dirs)
+        })
         .flatten()
     {
-        if entry.path() == opt.root {
+        if entry.path() == root {
             continue;
         }
-        if entry.path().is_dir() {
-            map.insert(entry.path().to_owned(), (vec![], 1, false));
+        // `entry.file_type()` (unlike `entry.path().is_dir()`, which always
+        // dereferences) respects `follow_links`: a symlink to a directory
+        // reports as a symlink, not a directory, when `--follow-symlinks`
+        // isn't set, so it falls into the file branch below and is counted
+        // as the single link inode instead of being queued for a full
+        // recursive count of its target.
+        if entry.file_type().is_dir() {
+            let size = if opt.size {
+                entry_size(&entry, opt.apparent_size)
+            } else {
+                0
+            };
+            map.insert(entry.path().to_owned(), (vec![], 1, size, false, vec![]));
             if let Some(parent) = entry.path().parent() {
                 map.get_mut(parent)
                     .context(format!("Parent {parent:?} not found."))?
@@ -78,136 +468,202 @@ fn main() -> Result<()> {
                 to_count.push(entry.path().to_owned())
             }
         } else {
-            map.get_mut(
-                entry
-                    .path()
-                    .parent()
-                    .context(format!("Parent of {entry:?} not found."))?,
-            )
-            .context(format!("Could not find {entry:?} parent in map"))?
-            .1 += 1;
+            if should_count_entry(&entry, opt.dedup_inodes, &seen_inodes) {
+                let size = if opt.size {
+                    entry_size(&entry, opt.apparent_size)
+                } else {
+                    0
+                };
+                let node = map
+                    .get_mut(
+                        entry
+                            .path()
+                            .parent()
+                            .context(format!("Parent of {entry:?} not found."))?,
+                    )
+                    .context(format!("Could not find {entry:?} parent in map"))?;
+                node.1 += 1;
+                node.2 += size;
+                node.4.push(entry.path().to_owned());
+            }
         }
     }
 
     let counts: Vec<_> = to_count
         .par_iter()
-        .map(move |entry| {
-            let count = count_dir_inodes(entry, opt.show_hidden);
-            (entry, count)
+        .map(|entry| {
+            let mtime = mtime_secs(entry);
+            // Only reuse the cache when this subtree's own mtime is still the
+            // one it was counted under; a changed directory always falls
+            // through to a fresh `count_dir_inodes`, leaving unchanged
+            // siblings untouched.
+            let cached = cache
+                .and_then(|cache| cache.subtrees.get(entry))
+                .filter(|cached| Some(cached.mtime) == mtime);
+            let count = match cached {
+                Some(cached) => Ok((cached.count, cached.size)),
+                None => {
+                    let base_ancestors = if opt.follow_symlinks {
+                        ancestor_keys(entry, root)
+                    } else {
+                        HashSet::new()
+                    };
+                    count_dir_inodes(
+                        entry,
+                        opt.show_hidden,
+                        opt.dedup_inodes,
+                        &seen_inodes,
+                        opt.size,
+                        opt.apparent_size,
+                        opt.one_file_system,
+                        root_dev,
+                        opt.follow_symlinks,
+                        &base_ancestors,
+                    )
+                }
+            };
+            (entry, count, mtime)
         })
         .collect();
 
-    for (entry, count) in counts {
-        let count = count.context(format!("Could not count inodes in {entry:?}"))?;
+    for (entry, count, mtime) in counts {
+        let (count, size) = count.context(format!("Could not count inodes in {entry:?}"))?;
         let child = map
             .get_mut(entry)
             .context(format!("Child {entry:?} not found"))?;
         child.1 += count;
-        child.2 = true;
+        child.2 += size;
+        child.3 = true;
+        if let Some(mtime) = mtime {
+            new_subtrees.insert(entry.clone(), CachedSubtree { mtime, count, size });
+        }
     }
 
-    update_node(&mut map, &opt.root)?;
-
-    let root_name = match opt.root.file_name() {
-        Some(p) => p.to_str(),
-        None => opt.root.to_str(),
-    }
-    .context(format!("Could not convert {:?} to string", opt.root))?;
+    update_node(map, root)?;
 
-    let root_node = map
-        .get(&opt.root)
-        .context(format!("Root node {:?} not found", opt.root))?
-        .clone();
-    let root_string = format_node(root_name, root_node.1, 100., opt.show_percent);
+    Ok(())
+}
 
-    let config = if opt.ignore_colors {
-        PrintConfig::default()
+fn format_node(
+    name: &str,
+    count: usize,
+    size: u64,
+    percent: f32,
+    show_percent: bool,
+    show_size: bool,
+) -> String {
+    let name = if show_percent {
+        name.bold().blue().underline().to_string()
     } else {
-        let mut config = PrintConfig::from_env();
-        config.branch = Style {
-            foreground: Some(Color::Blue),
-            ..Style::default()
-        };
-        config
+        name.bold().blue().to_string()
     };
-
-    if opt.depth == 0 {
-        println!("{root_string}");
-    } else {
-        let mut tree = TreeBuilder::new(root_string);
-        for child in root_node.0.iter().sorted_by(|a, b| {
-            let count_a = map.get(*a).unwrap().1;
-            let count_b = map.get(*b).unwrap().1;
-            Ord::cmp(&count_b, &count_a)
-        }) {
-            print_node(&mut tree, child, &mut map, root_node.1, opt.show_percent)?;
-        }
-        print_tree_with(&tree.build(), &config)?;
-        println!();
+    let mut parts = vec![name, format!("{count}").bold().red().to_string()];
+    if show_size {
+        parts.push(format_size(size).green().to_string());
     }
-
-    Ok(())
+    if show_percent {
+        parts.push(format!("({})", format!("{percent:.0}%").yellow()));
+    }
+    parts.join(" ")
 }
 
-fn format_node(name: &str, count: usize, percent: f32, show_percent: bool) -> String {
-    if show_percent {
-        format!(
-            "{} {} ({})",
-            name.bold().blue().underline(),
-            format!("{count}").bold().red(),
-            format!("{percent:.0}%").yellow()
-        )
+// Renders a byte count the way common disk-usage tools do: the largest unit
+// for which the value is at least 1, with one decimal place above bytes.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024. && unit < UNITS.len() - 1 {
+        value /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes}{}", UNITS[unit])
     } else {
-        format!("{} {}", name.bold().blue(), format!("{count}").bold().red())
+        format!("{value:.1}{}", UNITS[unit])
     }
 }
 
-fn update_node(map: &mut NodeMap, root: &Path) -> Result<usize> {
+fn update_node(map: &mut NodeMap, root: &Path) -> Result<(usize, u64)> {
     let mut node = map
         .get_mut(root)
         .context(format!("Root node {:?} not found", root))?
         .clone();
-    if !node.2 {
+    if !node.3 {
         let mut count = node.1;
+        let mut size = node.2;
         for child in node.0.clone() {
-            count += update_node(map, &child)?
+            let (child_count, child_size) = update_node(map, &child)?;
+            count += child_count;
+            size += child_size;
         }
         node.1 = count;
-        node.2 = true;
+        node.2 = size;
+        node.3 = true;
         map.insert(root.to_owned(), node);
-        Ok(count)
+        Ok((count, size))
     } else {
-        Ok(node.1)
+        Ok((node.1, node.2))
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn print_node(
     tree: &mut TreeBuilder,
-    root: &Path,
+    child: &Child,
     map: &mut NodeMap,
     total: usize,
     show_percent: bool,
+    show_size: bool,
+    sort: SortOrder,
+    reverse: bool,
+    threshold: usize,
 ) -> Result<()> {
-    let count = update_node(map, root)?;
-    let p: f32 = (count as f32 / total as f32) * 100.0;
-    let display_name = root
+    let display_name = child
+        .path()
         .file_name()
-        .context(format!("Could not find file name of {root:?}"))?
+        .context(format!("Could not find file name of {:?}", child.path()))?
         .to_str()
         .context("Could not convert filename to string")?;
-    tree.begin_child(format_node(display_name, count, p, show_percent));
-    let children = map
+
+    let root = match child {
+        Child::File(_) => {
+            // A plain file has no meaningful count of its own: it's a leaf,
+            // shown by name only.
+            tree.add_empty_child(display_name.bold().blue().to_string());
+            return Ok(());
+        }
+        Child::Dir(root) => root,
+    };
+
+    let (count, size) = update_node(map, root)?;
+    let p: f32 = (count as f32 / total as f32) * 100.0;
+    tree.begin_child(format_node(
+        display_name,
+        count,
+        size,
+        p,
+        show_percent,
+        show_size,
+    ));
+    let node = map
         .get(root)
-        .context(format!("Could not find {root:?} in map."))?
-        .0
-        .clone();
+        .context(format!("Could not find {root:?} in map."))?;
+    let dirs = node.0.clone();
+    let files = node.4.clone();
 
-    for child in children.iter().sorted_by(|a, b| {
-        let count_a = map.get(*a).unwrap().1;
-        let count_b = map.get(*b).unwrap().1;
-        Ord::cmp(&count_b, &count_a)
-    }) {
-        print_node(tree, child, map, total, show_percent)?;
+    for grandchild in ordered_children(&dirs, &files, map, sort, reverse, threshold) {
+        print_node(
+            tree,
+            &grandchild,
+            map,
+            total,
+            show_percent,
+            show_size,
+            sort,
+            reverse,
+            threshold,
+        )?;
     }
     tree.end_child();
 
@@ -222,23 +678,240 @@ fn is_hidden(entry: &walkdir::DirEntry) -> bool {
         .unwrap_or(false)
 }
 
-// Counts the number of inodes in a directory
-fn count_dir_inodes<P: AsRef<Path>>(root: P, show_hidden: bool) -> Result<usize> {
+// (dev, ino) pair identifying the inode backing a regular file, so hard
+// links sharing it can be recognized. Directories are never deduped: each
+// one always gets its own unique inode.
+#[cfg(unix)]
+fn inode_key(entry: &walkdir::DirEntry) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    entry.metadata().ok().map(|m| (m.dev(), m.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode_key(_entry: &walkdir::DirEntry) -> Option<(u64, u64)> {
+    None
+}
+
+// Whether `entry` should be counted: always true unless dedup is on and this
+// is a regular file whose (dev, ino) was already seen, in which case it is a
+// hard link to an inode we already counted. Entries whose metadata can't be
+// read, and non-Unix platforms where `inode_key` always returns `None`, fall
+// back to counting every entry as before.
+fn should_count_entry(
+    entry: &walkdir::DirEntry,
+    dedup_inodes: bool,
+    seen_inodes: &Mutex<HashSet<(u64, u64)>>,
+) -> bool {
+    if dedup_inodes && entry.file_type().is_file() {
+        match inode_key(entry) {
+            Some(key) => seen_inodes.lock().unwrap().insert(key),
+            None => true,
+        }
+    } else {
+        true
+    }
+}
+
+// Real on-disk usage (allocated blocks) on Unix, falling back to the
+// logical file length where block counts aren't available.
+#[cfg(unix)]
+fn on_disk_size(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn on_disk_size(metadata: &std::fs::Metadata) -> u64 {
+    metadata.len()
+}
+
+// Device id backing `metadata`. On non-Unix platforms there's no portable
+// way to read this, so every entry reports the same constant id, which makes
+// `on_device` a no-op there instead of needing a separate code path.
+#[cfg(unix)]
+fn device_id(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.dev()
+}
+
+#[cfg(not(unix))]
+fn device_id(_metadata: &std::fs::Metadata) -> u64 {
+    0
+}
+
+fn entry_dev(entry: &walkdir::DirEntry) -> Option<u64> {
+    entry.metadata().ok().map(|m| device_id(&m))
+}
+
+// Whether `entry` lives on the same filesystem as the root, when
+// `--one-file-system` is set. Entries whose metadata can't be read are
+// treated as off-device and skipped, consistent with permission-denied
+// handling elsewhere.
+fn on_device(entry: &walkdir::DirEntry, one_file_system: bool, root_dev: Option<u64>) -> bool {
+    if !one_file_system {
+        return true;
+    }
+    matches!((root_dev, entry_dev(entry)), (Some(root), Some(dev)) if root == dev)
+}
+
+#[cfg(unix)]
+fn path_inode_key(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+#[cfg(not(unix))]
+fn path_inode_key(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+// (dev, ino) of every strict ancestor of `start` up to and including `root`.
+// Used to seed a subtree walk's cycle guard with the directories that are
+// already "open" above it, so a followed symlink pointing back out of the
+// subtree is still caught.
+fn ancestor_keys(start: &Path, root: &Path) -> HashSet<(u64, u64)> {
+    let mut keys = HashSet::new();
+    if start == root {
+        return keys;
+    }
+    let mut current = start.parent();
+    while let Some(path) = current {
+        if let Some(key) = path_inode_key(path) {
+            keys.insert(key);
+        }
+        if path == root {
+            break;
+        }
+        current = path.parent();
+    }
+    keys
+}
+
+// Whether `entry` should be descended into when following symlinks: false if
+// its (dev, ino) is already open somewhere above it on the current path,
+// which means `--follow-symlinks` just walked into a cycle. `open_dirs`
+// tracks directories opened during this walk (popped once their depth is
+// left behind); `base_ancestors` seeds it with directories already open
+// before this walk started (see `ancestor_keys`). Entries whose metadata
+// can't be read are always allowed through (unlike `on_device`, which skips
+// them).
+fn allow_descent(
+    entry: &walkdir::DirEntry,
+    follow_symlinks: bool,
+    base_ancestors: &HashSet<(u64, u64)>,
+    open_dirs: &mut Vec<(u64, u64, usize)>,
+) -> bool {
+    if !follow_symlinks || !entry.file_type().is_dir() {
+        return true;
+    }
+    open_dirs.retain(|&(_, _, depth)| depth < entry.depth());
+    match inode_key(entry) {
+        Some(key) => {
+            if base_ancestors.contains(&key) || open_dirs.iter().any(|&(d, i, _)| (d, i) == key) {
+                false
+            } else {
+                open_dirs.push((key.0, key.1, entry.depth()));
+                true
+            }
+        }
+        None => true,
+    }
+}
+
+fn size_from_metadata(metadata: &std::fs::Metadata, apparent_size: bool) -> u64 {
+    if apparent_size {
+        metadata.len()
+    } else {
+        on_disk_size(metadata)
+    }
+}
+
+fn entry_size(entry: &walkdir::DirEntry, apparent_size: bool) -> u64 {
+    entry
+        .metadata()
+        .map_or(0, |metadata| size_from_metadata(&metadata, apparent_size))
+}
+
+// Counts the number of inodes (and, if requested, their aggregated size) in a directory
+#[allow(clippy::too_many_arguments)]
+fn count_dir_inodes<P: AsRef<Path>>(
+    root: P,
+    show_hidden: bool,
+    dedup_inodes: bool,
+    seen_inodes: &Mutex<HashSet<(u64, u64)>>,
+    show_size: bool,
+    apparent_size: bool,
+    one_file_system: bool,
+    root_dev: Option<u64>,
+    follow_symlinks: bool,
+    base_ancestors: &HashSet<(u64, u64)>,
+) -> Result<(usize, u64)> {
+    // `WalkDir` always dereferences its own root regardless of
+    // `follow_links`, so a symlinked root handed in here would be fully
+    // expanded even without `--follow-symlinks`. Callers are expected to
+    // only queue real directories (see the `file_type().is_dir()` check in
+    // `process_root`), but guard it here too rather than relying solely on
+    // that invariant.
+    if !follow_symlinks {
+        if let Ok(metadata) = std::fs::symlink_metadata(root.as_ref()) {
+            if metadata.file_type().is_symlink() {
+                // The link is this walk's root, already counted once (with
+                // its size) by the caller, so nothing more is added.
+                return Ok((0, 0));
+            }
+        }
+    }
+
     let mut count = 0;
+    let mut size = 0;
 
     let entries: Box<dyn Iterator<Item = walkdir::Result<walkdir::DirEntry>>> = if show_hidden {
-        Box::new(WalkDir::new(root).into_iter())
+        let mut open_dirs: Vec<(u64, u64, usize)> = vec![];
+        let base_ancestors = base_ancestors.clone();
+        Box::new(
+            WalkDir::new(root)
+                .follow_links(follow_symlinks)
+                .into_iter()
+                .filter_entry(move |e| {
+                    on_device(e, one_file_system, root_dev)
+                        && allow_descent(e, follow_symlinks, &base_ancestors, &mut open_dirs)
+                }),
+        )
     } else {
+        let mut open_dirs: Vec<(u64, u64, usize)> = vec![];
+        let base_ancestors = base_ancestors.clone();
         Box::new(
             WalkDir::new(root)
+                .follow_links(follow_symlinks)
                 .into_iter()
-                .filter_entry(|e| !is_hidden(e)),
+                .filter_entry(move |e| {
+                    !is_hidden(e)
+                        && on_device(e, one_file_system, root_dev)
+                        && allow_descent(e, follow_symlinks, &base_ancestors, &mut open_dirs)
+                }),
         )
     };
 
     for entry in entries {
-        match entry {
-            Ok(_) => {}
+        let counted = match entry {
+            Ok(entry) => {
+                let counted = should_count_entry(&entry, dedup_inodes, seen_inodes);
+                // `entry.depth() == 0` is this walk's root, already counted
+                // once (and its size already folded in) when `process_root`
+                // first inserted it into `map`; skip it here the same way
+                // `count - 1` below excludes it from the count.
+                if counted && show_size && entry.depth() != 0 {
+                    size += entry_size(&entry, apparent_size);
+                }
+                counted
+            }
+            Err(err) if err.loop_ancestor().is_some() => {
+                // `walkdir`'s own cycle detection (always active once
+                // `follow_links` is on) caught this symlink pointing back to
+                // one of its ancestors within this same walk. It's pruned,
+                // not counted, same as a cycle caught by `allow_descent`.
+                false
+            }
             Err(err) => {
                 let path = err.path().unwrap_or_else(|| Path::new("")).display();
                 if let Some(inner) = err.io_error() {
@@ -246,13 +919,330 @@ fn count_dir_inodes<P: AsRef<Path>>(root: P, show_hidden: bool) -> Result<usize>
                         io::ErrorKind::PermissionDenied => {
                             eprintln!("Permission denied for: {path}")
                         }
+                        io::ErrorKind::NotFound => {
+                            eprintln!("Broken symlink: {path}")
+                        }
                         _ => return Err(err.into()),
                     }
                 }
+                true
             }
         };
-        count += 1;
+        if counted {
+            count += 1;
+        }
+    }
+
+    Ok((count - 1, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Unique per-test scratch directory under the OS temp dir, removed at
+    // the end of the test.
+    #[cfg(unix)]
+    fn scratch_dir(name: &str) -> PathBuf {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("icounter_test_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
     }
 
-    Ok(count - 1)
+    // A minimal `Opt` with every flag off, for tests that only care about a
+    // couple of fields and would otherwise have to spell out the whole struct.
+    fn default_opt() -> Opt {
+        Opt {
+            show_hidden: false,
+            show_percent: false,
+            ignore_colors: false,
+            depth: 0,
+            dedup_inodes: false,
+            size: false,
+            apparent_size: false,
+            one_file_system: false,
+            cache: None,
+            sort: SortOrder::Count,
+            reverse: false,
+            threshold: 0,
+            follow_symlinks: false,
+            roots: vec![],
+        }
+    }
+
+    fn sample_flags() -> CacheFlags {
+        CacheFlags {
+            show_hidden: false,
+            dedup_inodes: false,
+            size: false,
+            apparent_size: false,
+            one_file_system: false,
+            follow_symlinks: false,
+        }
+    }
+
+    #[test]
+    fn validate_opt_rejects_cache_combined_with_size() {
+        let mut opt = default_opt();
+        opt.cache = Some(PathBuf::from("/tmp/whatever.bin"));
+        opt.size = true;
+        assert!(validate_opt(&opt).is_err());
+
+        opt.size = false;
+        assert!(validate_opt(&opt).is_ok());
+    }
+
+    #[test]
+    fn load_cache_rejects_a_flag_mismatch() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("icounter_test_cache_flags_{}.bin", std::process::id()));
+
+        let written = sample_flags();
+        let cache = Cache {
+            version: CACHE_VERSION,
+            flags: written.clone(),
+            subtrees: BTreeMap::new(),
+        };
+        save_cache(&path, &cache).unwrap();
+
+        assert!(load_cache(&path, &written).is_some());
+
+        let mut changed = written;
+        changed.follow_symlinks = true;
+        assert!(load_cache(&path, &changed).is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_cache_rejects_a_version_mismatch() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("icounter_test_cache_version_{}.bin", std::process::id()));
+
+        let flags = sample_flags();
+        let cache = Cache {
+            version: CACHE_VERSION - 1,
+            flags: flags.clone(),
+            subtrees: BTreeMap::new(),
+        };
+        save_cache(&path, &cache).unwrap();
+
+        assert!(load_cache(&path, &flags).is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ordered_children_sorts_files_after_directories_by_count() {
+        let mut map: NodeMap = BTreeMap::new();
+        let small = PathBuf::from("/root/small");
+        let big = PathBuf::from("/root/big");
+        map.insert(small.clone(), (vec![], 1, 0, true, vec![]));
+        map.insert(big.clone(), (vec![], 9, 0, true, vec![]));
+
+        let dirs = vec![small.clone(), big.clone()];
+        let files = vec![PathBuf::from("/root/b.txt"), PathBuf::from("/root/a.txt")];
+
+        let ordered = ordered_children(&dirs, &files, &map, SortOrder::Count, false, 0);
+
+        assert_eq!(
+            ordered,
+            vec![
+                Child::Dir(big),
+                Child::Dir(small),
+                Child::File(PathBuf::from("/root/a.txt")),
+                Child::File(PathBuf::from("/root/b.txt")),
+            ]
+        );
+    }
+
+    #[test]
+    fn ordered_children_drops_directories_below_threshold_but_keeps_files() {
+        let mut map: NodeMap = BTreeMap::new();
+        let tiny = PathBuf::from("/root/tiny");
+        map.insert(tiny.clone(), (vec![], 1, 0, true, vec![]));
+
+        let dirs = vec![tiny];
+        let files = vec![PathBuf::from("/root/f.txt")];
+
+        let ordered = ordered_children(&dirs, &files, &map, SortOrder::Count, false, 5);
+
+        assert_eq!(ordered, vec![Child::File(PathBuf::from("/root/f.txt"))]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn count_dir_inodes_follows_symlinks_without_looping_on_a_cycle() {
+        let base = scratch_dir("symlink_cycle");
+        let child = base.join("child");
+        std::fs::create_dir(&child).unwrap();
+        std::os::unix::fs::symlink(&base, child.join("back")).unwrap();
+
+        let seen_inodes = Mutex::new(HashSet::new());
+        let no_ancestors = HashSet::new();
+        let (count, _) = count_dir_inodes(
+            &base,
+            false,
+            false,
+            &seen_inodes,
+            false,
+            false,
+            false,
+            None,
+            true,
+            &no_ancestors,
+        )
+        .unwrap();
+
+        // `child` is counted; `child/back` cycles straight back to `base`
+        // (already open on this path) and is pruned rather than recursed.
+        assert_eq!(count, 1);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn count_dir_inodes_does_not_follow_symlinks_without_the_flag() {
+        let base = scratch_dir("symlink_off");
+        let target = base.join("target");
+        std::fs::create_dir(&target).unwrap();
+        std::fs::write(target.join("f"), b"x").unwrap();
+        std::os::unix::fs::symlink(&target, base.join("linked")).unwrap();
+
+        let seen_inodes = Mutex::new(HashSet::new());
+        let no_ancestors = HashSet::new();
+        let (count, _) = count_dir_inodes(
+            &base,
+            false,
+            false,
+            &seen_inodes,
+            false,
+            false,
+            false,
+            None,
+            false,
+            &no_ancestors,
+        )
+        .unwrap();
+
+        // Without `--follow-symlinks`, `target` (1), `target/f` (1) and
+        // `linked` (1, the link itself rather than a second walk of its
+        // target) are all counted once each.
+        assert_eq!(count, 3);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn count_dir_inodes_does_not_double_count_the_root_directory_size() {
+        let base = scratch_dir("size_no_doublecount");
+        std::fs::write(base.join("f"), b"x").unwrap();
+
+        // `base` itself is never counted by `count_dir_inodes` (its count and
+        // size are already folded in by `process_root`, which inserted it
+        // into `map` before handing it off here), so the only size that
+        // should come back is `f`'s.
+        let expected_size = size_from_metadata(&std::fs::metadata(base.join("f")).unwrap(), false);
+
+        let seen_inodes = Mutex::new(HashSet::new());
+        let no_ancestors = HashSet::new();
+        let (count, size) = count_dir_inodes(
+            &base,
+            false,
+            false,
+            &seen_inodes,
+            true,
+            false,
+            false,
+            None,
+            false,
+            &no_ancestors,
+        )
+        .unwrap();
+
+        assert_eq!(count, 1);
+        assert_eq!(size, expected_size);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn count_dir_inodes_dedups_hard_links_when_requested() {
+        let base = scratch_dir("dedup_inodes");
+        std::fs::write(base.join("a"), b"x").unwrap();
+        std::fs::hard_link(base.join("a"), base.join("b")).unwrap();
+
+        let no_ancestors = HashSet::new();
+
+        let seen_inodes = Mutex::new(HashSet::new());
+        let (count, _) = count_dir_inodes(
+            &base, false, false, &seen_inodes, false, false, false, None, false, &no_ancestors,
+        )
+        .unwrap();
+        assert_eq!(count, 2, "without dedup, each hard-linked entry counts separately");
+
+        let seen_inodes = Mutex::new(HashSet::new());
+        let (count, _) = count_dir_inodes(
+            &base, false, true, &seen_inodes, false, false, false, None, false, &no_ancestors,
+        )
+        .unwrap();
+        assert_eq!(count, 1, "with dedup, the second hard link to the same inode isn't recounted");
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn on_device_filters_out_entries_from_a_different_filesystem() {
+        let base = scratch_dir("one_file_system");
+        std::fs::write(base.join("f"), b"x").unwrap();
+        let root_dev = device_id(&std::fs::metadata(&base).unwrap());
+        let other_dev = root_dev.wrapping_add(1);
+
+        let entry = WalkDir::new(&base)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .find(|e| e.path().ends_with("f"))
+            .unwrap();
+
+        // Off by default: every entry passes regardless of device.
+        assert!(on_device(&entry, false, Some(other_dev)));
+        // On: only entries sharing the root's device pass.
+        assert!(on_device(&entry, true, Some(root_dev)));
+        assert!(!on_device(&entry, true, Some(other_dev)));
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn process_root_counts_multiple_roots_independently_into_one_map() {
+        let root_a = scratch_dir("multi_root_a");
+        std::fs::write(root_a.join("a1"), b"x").unwrap();
+        std::fs::write(root_a.join("a2"), b"x").unwrap();
+
+        let root_b = scratch_dir("multi_root_b");
+        std::fs::write(root_b.join("b1"), b"x").unwrap();
+        std::fs::write(root_b.join("b2"), b"x").unwrap();
+        std::fs::write(root_b.join("b3"), b"x").unwrap();
+
+        let opt = default_opt();
+        let mut map: NodeMap = BTreeMap::new();
+        let mut new_subtrees = BTreeMap::new();
+        process_root(&opt, &root_a, 1, &mut map, None, &mut new_subtrees).unwrap();
+        process_root(&opt, &root_b, 1, &mut map, None, &mut new_subtrees).unwrap();
+
+        // Each root's own files (plus itself) are counted, and merging a
+        // second root into the same map doesn't affect the first's tally.
+        assert_eq!(map.get(&root_a).unwrap().1, 3);
+        assert_eq!(map.get(&root_b).unwrap().1, 4);
+
+        std::fs::remove_dir_all(&root_a).unwrap();
+        std::fs::remove_dir_all(&root_b).unwrap();
+    }
 }